@@ -1,22 +1,47 @@
+use std::collections::BTreeMap;
 use std::fmt;
-use std::thread;
 use std::time::Duration;
 
 use super::entities;
 use crate::error::{Error, Kind};
 use crate::streaming::{Message, Streaming};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+
+use futures_channel::{mpsc, oneshot};
+use rand::Rng;
 
+#[cfg(not(target_arch = "wasm32"))]
+use std::thread;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
+#[cfg(not(target_arch = "wasm32"))]
 use futures_util::{SinkExt, StreamExt};
+#[cfg(not(target_arch = "wasm32"))]
 use tokio::runtime::Runtime;
+#[cfg(not(target_arch = "wasm32"))]
 use tokio_tungstenite::{
     connect_async, tungstenite::protocol::frame::coding::CloseCode,
     tungstenite::protocol::Message as WebSocketMessage,
 };
+#[cfg(not(target_arch = "wasm32"))]
 use url::Url;
 
-const RECONNECT_INTERVAL: u64 = 5000;
+#[cfg(target_arch = "wasm32")]
+use futures_util::future::FutureExt;
+#[cfg(target_arch = "wasm32")]
+use futures_util::{pin_mut, SinkExt, StreamExt};
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen_futures::spawn_local;
+#[cfg(target_arch = "wasm32")]
+use ws_stream_wasm::{ObserveConfig, WsEvent, WsMessage, WsMeta};
+
+#[cfg(not(target_arch = "wasm32"))]
 const READ_MESSAGE_TIMEOUT_SECONDS: u64 = 60;
+const DEFAULT_KEEPALIVE_INTERVAL_SECONDS: u64 = 30;
+/// The WebSocket close code for a normal closure (equivalent to
+/// `tungstenite`'s `CloseCode::Normal`, which the browser API doesn't expose).
+#[cfg(target_arch = "wasm32")]
+const WASM_NORMAL_CLOSE_CODE: u16 = 1000;
 
 #[derive(Debug, Clone)]
 pub struct WebSocket {
@@ -24,6 +49,51 @@ pub struct WebSocket {
     stream: String,
     params: Option<Vec<String>>,
     access_token: Option<String>,
+    keepalive_interval: Duration,
+    reconnect_policy: ReconnectPolicy,
+}
+
+/// Controls how [`WebSocket`] backs off between reconnect attempts.
+///
+/// The delay starts at `base_delay` and is multiplied by `factor` (2.0 by
+/// default) after each consecutive failed attempt, up to `max_delay`, with
+/// random jitter added to avoid a thundering
+/// herd of reconnects against a rate-limited instance. The counter resets once a
+/// connection is established successfully. If `max_retries` is set, `connect`
+/// gives up and surfaces an error instead of retrying forever.
+#[derive(Debug, Clone, Copy)]
+pub struct ReconnectPolicy {
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub factor: f64,
+    pub max_retries: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            factor: 2.0,
+            max_retries: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Computes the delay before the next reconnect attempt. The exponential part
+    /// is clamped to `max_delay` before it's ever turned into a `Duration`, and
+    /// then up to 20% of random jitter is added on top of it. Clamping the
+    /// `Duration` after the fact doesn't help: `base_delay * factor.powi(n)` can
+    /// exceed the range of a `Duration` long before that, which panics when
+    /// constructing the (already too large) `Duration`.
+    fn delay_for(&self, attempt: u32) -> Duration {
+        let base_secs = self.base_delay.as_secs_f64();
+        let max_secs = self.max_delay.as_secs_f64();
+        let capped_secs = (base_secs * self.factor.powi(attempt as i32)).min(max_secs);
+        let jitter_secs = capped_secs * rand::thread_rng().gen_range(0.0..0.2);
+        Duration::from_secs_f64(capped_secs + jitter_secs)
+    }
 }
 
 #[derive(Deserialize)]
@@ -32,6 +102,66 @@ struct RawMessage {
     payload: String,
 }
 
+#[derive(Debug, Clone, Serialize)]
+struct StreamCommand {
+    #[serde(rename = "type")]
+    command_type: &'static str,
+    stream: String,
+    #[serde(flatten)]
+    params: BTreeMap<String, String>,
+}
+
+/// Decodes a single text frame's JSON payload into a [`Message`]. Shared by the
+/// native (tokio-tungstenite) and wasm (browser `WebSocket`) transports so the
+/// event decoding logic only lives in one place.
+fn parse_text(text: &str) -> Result<Message, Error> {
+    let mes = serde_json::from_str::<RawMessage>(text)?;
+    match &*mes.event {
+        "update" => {
+            let res = serde_json::from_str::<entities::Status>(&mes.payload).map_err(|e| {
+                log::error!(
+                    "failed to parse status: {}\n{}",
+                    e.to_string(),
+                    &mes.payload
+                );
+                e
+            })?;
+            Ok(Message::Update(res.into()))
+        }
+        "notification" => {
+            let res =
+                serde_json::from_str::<entities::Notification>(&mes.payload).map_err(|e| {
+                    log::error!(
+                        "failed to parse notification: {}\n{}",
+                        e.to_string(),
+                        &mes.payload
+                    );
+                    e
+                })?;
+            Ok(Message::Notification(res.into()))
+        }
+        "conversation" => {
+            let res =
+                serde_json::from_str::<entities::Conversation>(&mes.payload).map_err(|e| {
+                    log::error!(
+                        "failed to parse conversation: {}\n{}",
+                        e.to_string(),
+                        &mes.payload
+                    );
+                    e
+                })?;
+            Ok(Message::Conversation(res.into()))
+        }
+        "delete" => Ok(Message::Delete(mes.payload)),
+        event => Err(Error::new_own(
+            format!("Unknown event is received: {}", event),
+            Kind::ParseError,
+            None,
+            None,
+        )),
+    }
+}
+
 impl WebSocket {
     pub fn new(
         url: String,
@@ -44,99 +174,222 @@ impl WebSocket {
             stream,
             params,
             access_token,
+            keepalive_interval: Duration::from_secs(DEFAULT_KEEPALIVE_INTERVAL_SECONDS),
+            reconnect_policy: ReconnectPolicy::default(),
         }
     }
 
-    fn parse(&self, message: WebSocketMessage) -> Result<Message, Error> {
-        if message.is_ping() || message.is_pong() {
-            Ok(Message::Heartbeat())
-        } else if message.is_text() {
-            let text = message.to_text()?;
-            let mes = serde_json::from_str::<RawMessage>(text)?;
-            match &*mes.event {
-                "update" => {
-                    let res =
-                        serde_json::from_str::<entities::Status>(&mes.payload).map_err(|e| {
-                            log::error!(
-                                "failed to parse status: {}\n{}",
-                                e.to_string(),
-                                &mes.payload
-                            );
-                            e
-                        })?;
-                    Ok(Message::Update(res.into()))
-                }
-                "notification" => {
-                    let res = serde_json::from_str::<entities::Notification>(&mes.payload)
-                        .map_err(|e| {
-                            log::error!(
-                                "failed to parse notification: {}\n{}",
-                                e.to_string(),
-                                &mes.payload
-                            );
-                            e
-                        })?;
-                    Ok(Message::Notification(res.into()))
-                }
-                "conversation" => {
-                    let res = serde_json::from_str::<entities::Conversation>(&mes.payload)
-                        .map_err(|e| {
-                            log::error!(
-                                "failed to parse conversation: {}\n{}",
-                                e.to_string(),
-                                &mes.payload
-                            );
-                            e
-                        })?;
-                    Ok(Message::Conversation(res.into()))
-                }
-                "delete" => Ok(Message::Delete(mes.payload)),
-                event => Err(Error::new_own(
-                    format!("Unknown event is received: {}", event),
-                    Kind::ParseError,
-                    None,
-                    None,
-                )),
-            }
-        } else {
-            Err(Error::new_own(
-                String::from("Receiving message is not ping, pong or text"),
-                Kind::ParseError,
-                None,
-                None,
-            ))
+    /// Overrides the interval at which keepalive pings are sent. Defaults to 30s.
+    /// Has no effect on `wasm32`, where the browser answers protocol pings itself.
+    pub fn keepalive_interval(mut self, interval: Duration) -> Self {
+        self.keepalive_interval = interval;
+        self
+    }
+
+    /// Overrides the reconnection backoff policy. Defaults to [`ReconnectPolicy::default`].
+    pub fn reconnect_policy(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect_policy = policy;
+        self
+    }
+
+    fn build_url(&self) -> String {
+        let mut parameter = Vec::<String>::from([format!("stream={}", self.stream)]);
+        if let Some(access_token) = &self.access_token {
+            parameter.push(format!("access_token={}", access_token));
+        }
+        if let Some(mut params) = self.params.clone() {
+            parameter.append(&mut params);
         }
+        self.url.clone() + "?" + parameter.join("&").as_str()
+    }
+}
+
+/// A handle to a running connection started by [`WebSocket::stream`] or
+/// [`WebSocket::listen_with_shutdown`]. Lets callers stop the connection, or
+/// subscribe/unsubscribe additional timelines multiplexed over the same socket;
+/// active subscriptions are automatically re-sent after a reconnect.
+pub struct Handle {
+    shutdown: oneshot::Sender<()>,
+    commands: mpsc::UnboundedSender<StreamCommand>,
+}
+
+impl Handle {
+    pub fn shutdown(self) {
+        let _ = self.shutdown.send(());
+    }
+
+    pub fn subscribe(&self, stream: impl Into<String>, params: BTreeMap<String, String>) {
+        let _ = self.commands.unbounded_send(StreamCommand {
+            command_type: "subscribe",
+            stream: stream.into(),
+            params,
+        });
+    }
+
+    pub fn unsubscribe(&self, stream: impl Into<String>) {
+        let _ = self.commands.unbounded_send(StreamCommand {
+            command_type: "unsubscribe",
+            stream: stream.into(),
+            params: BTreeMap::new(),
+        });
     }
+}
+
+#[derive(thiserror::Error)]
+#[error("{kind}")]
+struct InnerError {
+    kind: InnerKind,
+}
 
-    fn connect(&self, url: &str, callback: Box<dyn Fn(Message)>) {
+#[derive(Debug, thiserror::Error)]
+enum InnerKind {
+    #[error("connection error")]
+    ConnectionError,
+    #[error("socket read error")]
+    SocketReadError,
+    #[error("unusual socket close error")]
+    UnusualSocketCloseError,
+    #[cfg(not(target_arch = "wasm32"))]
+    #[error("timeout error")]
+    TimeoutError,
+}
+
+impl InnerError {
+    pub fn new(kind: InnerKind) -> Self {
+        Self { kind }
+    }
+}
+
+impl fmt::Debug for InnerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut builder = f.debug_struct("megalodon::pleroma::web_socket::InnerError");
+
+        builder.field("kind", &self.kind);
+        builder.finish()
+    }
+}
+
+impl WebSocket {
+    /// Drives the reconnect/backoff loop, delegating the actual socket I/O for
+    /// each attempt to the transport-specific `do_connect`. Shared between the
+    /// native and wasm transports so the backoff/lifecycle bookkeeping only
+    /// lives in one place; `sleep_or_shutdown` is the only part that differs
+    /// per transport (no tokio reactor on wasm32).
+    async fn connect(
+        &self,
+        tx: mpsc::UnboundedSender<Result<Message, Error>>,
+        mut shutdown: oneshot::Receiver<()>,
+        mut command_rx: mpsc::UnboundedReceiver<StreamCommand>,
+    ) {
+        let url = self.build_url();
+        let mut subscriptions = BTreeMap::<String, BTreeMap<String, String>>::new();
+        let mut attempt: u32 = 0;
         loop {
-            match Runtime::new()
-                .unwrap()
-                .block_on(self.do_connect(url, &callback))
+            match self
+                .do_connect(
+                    &url,
+                    &tx,
+                    &mut shutdown,
+                    &mut command_rx,
+                    &mut subscriptions,
+                    &mut attempt,
+                )
+                .await
             {
-                Ok(()) => {
+                Ok(true) => {
+                    log::info!("connection for {} is closed by shutdown request", url);
+                    return;
+                }
+                Ok(false) => {
                     log::info!("connection for {} is  closed", url);
                     return;
                 }
-                Err(err) => match err.kind {
-                    InnerKind::ConnectionError
-                    | InnerKind::SocketReadError
-                    | InnerKind::UnusualSocketCloseError
-                    | InnerKind::TimeoutError => {
-                        thread::sleep(Duration::from_millis(RECONNECT_INTERVAL));
-                        log::info!("Reconnecting to {}", url);
-                        continue;
+                Err(err) => {
+                    // A single lifecycle notification per failure: `Message::Error`
+                    // already carries this as a data item, so a raw `Err` here would
+                    // just report the same failure twice.
+                    let _ = tx.unbounded_send(Ok(Message::Error(err.to_string())));
+                    if let Some(max_retries) = self.reconnect_policy.max_retries {
+                        if attempt >= max_retries {
+                            let _ = tx.unbounded_send(Err(Error::new_own(
+                                format!("Exceeded max reconnect attempts ({})", max_retries),
+                                Kind::ParseError,
+                                None,
+                                None,
+                            )));
+                            return;
+                        }
+                    }
+                    let delay = self.reconnect_policy.delay_for(attempt);
+                    attempt += 1;
+                    let _ = tx.unbounded_send(Ok(Message::Reconnecting { attempt, delay }));
+                    if Self::sleep_or_shutdown(&mut shutdown, delay).await {
+                        log::info!("Shutdown requested while reconnecting to {}", url);
+                        return;
                     }
-                },
+                    log::info!("Reconnecting to {} (attempt {})", url, attempt);
+                    continue;
+                }
             }
         }
     }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl WebSocket {
+    fn parse(&self, message: WebSocketMessage) -> Result<Message, Error> {
+        if message.is_text() {
+            parse_text(message.to_text()?)
+        } else {
+            Err(Error::new_own(
+                String::from("Receiving message is not text"),
+                Kind::ParseError,
+                None,
+                None,
+            ))
+        }
+    }
+
+    /// Connects to the streaming endpoint and returns a stream of parsed messages
+    /// along with a [`Handle`] that can be used to manage the connection: shut it
+    /// down, or subscribe/unsubscribe additional timelines multiplexed over the
+    /// same socket.
+    ///
+    /// The socket is driven by a background task spawned onto the caller's tokio
+    /// runtime, so the stream can be consumed with `StreamExt` combinators
+    /// (`.next().await`, `.for_each`, ...) instead of blocking on a callback.
+    pub fn stream(&self) -> (mpsc::UnboundedReceiver<Result<Message, Error>>, Handle) {
+        let (tx, rx) = mpsc::unbounded();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let (command_tx, command_rx) = mpsc::unbounded();
+        let this = self.clone();
+        tokio::spawn(async move { this.connect(tx, shutdown_rx, command_rx).await });
+        (
+            rx,
+            Handle {
+                shutdown: shutdown_tx,
+                commands: command_tx,
+            },
+        )
+    }
 
+    /// Runs the read loop for a single connection attempt. Returns `Ok(true)` if a
+    /// shutdown was requested, `Ok(false)` if the server closed the socket normally.
+    ///
+    /// `attempt` is reset to 0 once the handshake below succeeds, so the backoff
+    /// counter in `connect` only resets on an actual successful connection rather
+    /// than on error kind, which would never let `max_retries` trigger for a
+    /// connect-then-drop failure loop.
     async fn do_connect(
         &self,
         url: &str,
-        callback: &Box<dyn Fn(Message)>,
-    ) -> Result<(), InnerError> {
+        tx: &mpsc::UnboundedSender<Result<Message, Error>>,
+        shutdown: &mut oneshot::Receiver<()>,
+        command_rx: &mut mpsc::UnboundedReceiver<StreamCommand>,
+        subscriptions: &mut BTreeMap<String, BTreeMap<String, String>>,
+        attempt: &mut u32,
+    ) -> Result<bool, InnerError> {
+        let _ = tx.unbounded_send(Ok(Message::Connecting()));
         let (mut socket, response) =
             connect_async(Url::parse(url).unwrap()).await.map_err(|e| {
                 log::error!("Failed to connect: {}", e);
@@ -149,13 +402,62 @@ impl WebSocket {
         for (ref header, _value) in response.headers() {
             log::debug!("* {}", header);
         }
+        let _ = tx.unbounded_send(Ok(Message::Connected()));
+        *attempt = 0;
+
+        for (stream, params) in subscriptions.iter() {
+            let command = StreamCommand {
+                command_type: "subscribe",
+                stream: stream.clone(),
+                params: params.clone(),
+            };
+            if let Ok(json) = serde_json::to_string(&command) {
+                let _ = socket.send(WebSocketMessage::Text(json)).await;
+            }
+        }
+
+        let mut keepalive = tokio::time::interval(self.keepalive_interval);
+        keepalive.tick().await;
+        let mut last_ping_sent_at: Option<Instant> = None;
 
         loop {
-            let res = tokio::time::timeout(
-                Duration::from_secs(READ_MESSAGE_TIMEOUT_SECONDS),
-                socket.next(),
-            )
-            .await
+            let res = tokio::select! {
+                _ = &mut *shutdown => {
+                    log::info!("Shutdown requested for {}", url);
+                    let _ = socket.send(WebSocketMessage::Close(None)).await;
+                    return Ok(true);
+                }
+                command = command_rx.next() => {
+                    let Some(command) = command else {
+                        continue;
+                    };
+                    match command.command_type {
+                        "subscribe" => {
+                            subscriptions.insert(command.stream.clone(), command.params.clone());
+                        }
+                        "unsubscribe" => {
+                            subscriptions.remove(&command.stream);
+                        }
+                        _ => {}
+                    }
+                    if let Ok(json) = serde_json::to_string(&command) {
+                        let _ = socket.send(WebSocketMessage::Text(json)).await;
+                    }
+                    continue;
+                }
+                _ = keepalive.tick() => {
+                    last_ping_sent_at = Some(Instant::now());
+                    let _ = socket.send(WebSocketMessage::Ping(Vec::new())).await.map_err(|e| {
+                        log::error!("Failed to send keepalive ping: {:#?}", e);
+                        e
+                    });
+                    continue;
+                }
+                res = tokio::time::timeout(
+                    Duration::from_secs(READ_MESSAGE_TIMEOUT_SECONDS),
+                    socket.next(),
+                ) => res,
+            }
             .map_err(|e| {
                 log::error!("Timeout reading message: {}", e);
                 InnerError::new(InnerKind::TimeoutError)
@@ -177,6 +479,14 @@ impl WebSocket {
                         e
                     });
             }
+            if msg.is_pong() {
+                if let Some(sent_at) = last_ping_sent_at.take() {
+                    let _ = tx.unbounded_send(Ok(Message::Heartbeat(sent_at.elapsed())));
+                }
+            }
+            if msg.is_ping() || msg.is_pong() {
+                continue;
+            }
             if msg.is_close() {
                 let _ = socket.close(None).await.map_err(|e| {
                     log::error!("{:#?}", e);
@@ -184,15 +494,18 @@ impl WebSocket {
                 });
                 if let WebSocketMessage::Close(Some(close)) = msg {
                     log::warn!("Connection to {} is closed because {}", url, close.code);
+                    let _ = tx.unbounded_send(Ok(Message::Closed {
+                        code: u16::from(close.code),
+                    }));
                     if close.code != CloseCode::Normal {
                         return Err(InnerError::new(InnerKind::UnusualSocketCloseError));
                     }
                 }
-                return Ok(());
+                return Ok(false);
             }
             match self.parse(msg) {
                 Ok(message) => {
-                    callback(message);
+                    let _ = tx.unbounded_send(Ok(message));
                 }
                 Err(err) => {
                     log::warn!("{}", err);
@@ -200,53 +513,318 @@ impl WebSocket {
             }
         }
     }
+
+    /// Waits for either `delay` to elapse or a shutdown signal, whichever comes
+    /// first. Returns `true` if shutdown fired. Used by the shared `connect`
+    /// backoff loop, which is otherwise identical between transports.
+    async fn sleep_or_shutdown(shutdown: &mut oneshot::Receiver<()>, delay: Duration) -> bool {
+        tokio::select! {
+            _ = &mut *shutdown => true,
+            _ = tokio::time::sleep(delay) => false,
+        }
+    }
+
+    /// Like [`Streaming::listen`], but runs on a dedicated thread and returns a
+    /// [`Handle`] that can be used from elsewhere to stop the loop or manage
+    /// subscriptions.
+    pub fn listen_with_shutdown(&self, callback: Box<dyn Fn(Message) + Send>) -> Handle {
+        let (tx, rx) = mpsc::unbounded();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let (command_tx, command_rx) = mpsc::unbounded();
+        let this = self.clone();
+        thread::spawn(move || {
+            let rt = Runtime::new().unwrap();
+            rt.block_on(async {
+                let connect = this.connect(tx, shutdown_rx, command_rx);
+                let forward = async {
+                    let mut rx = rx;
+                    while let Some(message) = rx.next().await {
+                        match message {
+                            Ok(message) => callback(message),
+                            Err(err) => log::warn!("{}", err),
+                        }
+                    }
+                };
+                tokio::join!(connect, forward);
+            });
+        });
+        Handle {
+            shutdown: shutdown_tx,
+            commands: command_tx,
+        }
+    }
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 impl Streaming for WebSocket {
     fn listen(&self, callback: Box<dyn Fn(Message)>) {
-        let mut parameter = Vec::<String>::from([format!("stream={}", self.stream)]);
-        if let Some(access_token) = &self.access_token {
-            parameter.push(format!("access_token={}", access_token));
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mut rx, _handle) = self.stream();
+            while let Some(message) = rx.next().await {
+                match message {
+                    Ok(message) => callback(message),
+                    Err(err) => log::warn!("{}", err),
+                }
+            }
+        });
+    }
+}
+
+// The wasm32 transport swaps tokio-tungstenite for the browser `WebSocket` (via
+// `ws_stream_wasm`), since there is no tokio reactor available in a browser tab.
+// It keeps the same `parse_text` decoding and `Handle`/subscription surface as
+// the native transport above.
+#[cfg(target_arch = "wasm32")]
+impl WebSocket {
+    fn parse(&self, message: WsMessage) -> Result<Message, Error> {
+        match message {
+            WsMessage::Text(text) => parse_text(&text),
+            WsMessage::Binary(_) => Err(Error::new_own(
+                String::from("Receiving message is not text"),
+                Kind::ParseError,
+                None,
+                None,
+            )),
         }
-        if let Some(mut params) = self.params.clone() {
-            parameter.append(&mut params);
+    }
+
+    /// Connects to the streaming endpoint and returns a stream of parsed messages
+    /// along with a [`Handle`], driving the browser `WebSocket` from a task spawned
+    /// onto the local `wasm-bindgen` event loop via `spawn_local`.
+    pub fn stream(&self) -> (mpsc::UnboundedReceiver<Result<Message, Error>>, Handle) {
+        let (tx, rx) = mpsc::unbounded();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let (command_tx, command_rx) = mpsc::unbounded();
+        let this = self.clone();
+        spawn_local(async move { this.connect(tx, shutdown_rx, command_rx).await });
+        (
+            rx,
+            Handle {
+                shutdown: shutdown_tx,
+                commands: command_tx,
+            },
+        )
+    }
+
+    /// Runs the read loop for a single connection attempt. Returns `Ok(true)` if a
+    /// shutdown was requested, `Ok(false)` if the server closed the socket normally.
+    ///
+    /// There is no control-frame API on the browser `WebSocket`, so unlike the
+    /// native transport this does not send its own keepalive pings or measure
+    /// round-trip latency; the browser answers protocol pings transparently.
+    /// `keepalive_interval` is still read below (and otherwise ignored) purely so
+    /// it isn't flagged as dead code on this target.
+    ///
+    /// `attempt` is reset to 0 once the handshake below succeeds, mirroring the
+    /// native transport's backoff bookkeeping.
+    async fn do_connect(
+        &self,
+        url: &str,
+        tx: &mpsc::UnboundedSender<Result<Message, Error>>,
+        shutdown: &mut oneshot::Receiver<()>,
+        command_rx: &mut mpsc::UnboundedReceiver<StreamCommand>,
+        subscriptions: &mut BTreeMap<String, BTreeMap<String, String>>,
+        attempt: &mut u32,
+    ) -> Result<bool, InnerError> {
+        let _ = self.keepalive_interval;
+        let _ = tx.unbounded_send(Ok(Message::Connecting()));
+        let (mut meta, mut socket) = WsMeta::connect(url, None).await.map_err(|e| {
+            log::error!("Failed to connect: {:?}", e);
+            InnerError::new(InnerKind::ConnectionError)
+        })?;
+        let mut events = meta.observe(ObserveConfig::default()).await.map_err(|e| {
+            log::error!("Failed to observe websocket events for {}: {:?}", url, e);
+            InnerError::new(InnerKind::ConnectionError)
+        })?;
+        log::debug!("Connected to {}", url);
+        let _ = tx.unbounded_send(Ok(Message::Connected()));
+        *attempt = 0;
+
+        for (stream, params) in subscriptions.iter() {
+            let command = StreamCommand {
+                command_type: "subscribe",
+                stream: stream.clone(),
+                params: params.clone(),
+            };
+            if let Ok(json) = serde_json::to_string(&command) {
+                let _ = socket.send(WsMessage::Text(json)).await;
+            }
         }
-        let mut url = self.url.clone();
-        url = url + "?" + parameter.join("&").as_str();
 
-        self.connect(url.as_str(), callback);
+        loop {
+            let shutdown_fut = (&mut *shutdown).fuse();
+            let command_fut = command_rx.next().fuse();
+            let socket_fut = socket.next().fuse();
+            let events_fut = events.next().fuse();
+            pin_mut!(shutdown_fut, command_fut, socket_fut, events_fut);
+
+            futures_util::select! {
+                _ = shutdown_fut => {
+                    log::info!("Shutdown requested for {}", url);
+                    let _ = socket.close().await;
+                    return Ok(true);
+                }
+                command = command_fut => {
+                    let Some(command) = command else {
+                        continue;
+                    };
+                    match command.command_type {
+                        "subscribe" => {
+                            subscriptions.insert(command.stream.clone(), command.params.clone());
+                        }
+                        "unsubscribe" => {
+                            subscriptions.remove(&command.stream);
+                        }
+                        _ => {}
+                    }
+                    if let Ok(json) = serde_json::to_string(&command) {
+                        let _ = socket.send(WsMessage::Text(json)).await;
+                    }
+                }
+                event = events_fut => {
+                    let Some(WsEvent::Closed(close)) = event else {
+                        continue;
+                    };
+                    log::warn!("Connection to {} is closed because {}", url, close.code);
+                    let _ = tx.unbounded_send(Ok(Message::Closed { code: close.code }));
+                    if close.code != WASM_NORMAL_CLOSE_CODE {
+                        return Err(InnerError::new(InnerKind::UnusualSocketCloseError));
+                    }
+                    return Ok(false);
+                }
+                msg = socket_fut => {
+                    let Some(msg) = msg else {
+                        // The socket stream ended; wait for the matching close
+                        // event (carrying the close code) rather than racing it
+                        // against a socket stream that would just resolve to
+                        // `None` again on every further poll.
+                        loop {
+                            match events.next().await {
+                                Some(WsEvent::Closed(close)) => {
+                                    log::warn!(
+                                        "Connection to {} is closed because {}",
+                                        url,
+                                        close.code
+                                    );
+                                    let _ = tx.unbounded_send(Ok(Message::Closed {
+                                        code: close.code,
+                                    }));
+                                    if close.code != WASM_NORMAL_CLOSE_CODE {
+                                        return Err(InnerError::new(
+                                            InnerKind::UnusualSocketCloseError,
+                                        ));
+                                    }
+                                    return Ok(false);
+                                }
+                                Some(_) => continue,
+                                None => return Ok(false),
+                            }
+                        }
+                    };
+                    match self.parse(msg) {
+                        Ok(message) => {
+                            let _ = tx.unbounded_send(Ok(message));
+                        }
+                        Err(err) => {
+                            log::warn!("{}", err);
+                        }
+                    }
+                }
+            }
+        }
     }
-}
 
-#[derive(thiserror::Error)]
-#[error("{kind}")]
-struct InnerError {
-    kind: InnerKind,
+    /// Waits for either `delay` to elapse or a shutdown signal, whichever comes
+    /// first. Returns `true` if shutdown fired. Used by the shared `connect`
+    /// backoff loop, which is otherwise identical between transports.
+    async fn sleep_or_shutdown(shutdown: &mut oneshot::Receiver<()>, delay: Duration) -> bool {
+        let shutdown_fut = (&mut *shutdown).fuse();
+        let sleep_fut = gloo_timers::future::TimeoutFuture::new(delay.as_millis() as u32).fuse();
+        pin_mut!(shutdown_fut, sleep_fut);
+        futures_util::select! {
+            _ = shutdown_fut => true,
+            _ = sleep_fut => false,
+        }
+    }
 }
 
-#[derive(Debug, thiserror::Error)]
-enum InnerKind {
-    #[error("connection error")]
-    ConnectionError,
-    #[error("socket read error")]
-    SocketReadError,
-    #[error("unusual socket close error")]
-    UnusualSocketCloseError,
-    #[error("timeout error")]
-    TimeoutError,
+// `listen`/`listen_with_shutdown` block the calling thread with a tokio `Runtime`,
+// which does not exist on wasm32; browser callers should drive `stream()` with
+// `StreamExt` combinators on the `wasm-bindgen` event loop instead.
+#[cfg(target_arch = "wasm32")]
+impl Streaming for WebSocket {
+    fn listen(&self, callback: Box<dyn Fn(Message)>) {
+        let (mut rx, handle) = self.stream();
+        spawn_local(async move {
+            // Dropping the handle would fire the shutdown signal, so keep it alive
+            // for as long as messages are being forwarded.
+            let _handle = handle;
+            while let Some(message) = rx.next().await {
+                match message {
+                    Ok(message) => callback(message),
+                    Err(err) => log::warn!("{}", err),
+                }
+            }
+        });
+    }
 }
 
-impl InnerError {
-    pub fn new(kind: InnerKind) -> Self {
-        Self { kind }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subscribe_command_flattens_params_into_the_frame() {
+        let mut params = BTreeMap::new();
+        params.insert("tag".to_string(), "rust".to_string());
+        let command = StreamCommand {
+            command_type: "subscribe",
+            stream: "hashtag".to_string(),
+            params,
+        };
+        let json = serde_json::to_string(&command).unwrap();
+        assert_eq!(json, r#"{"type":"subscribe","stream":"hashtag","tag":"rust"}"#);
     }
-}
 
-impl fmt::Debug for InnerError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut builder = f.debug_struct("megalodon::pleroma::web_socket::InnerError");
+    #[test]
+    fn unsubscribe_command_carries_no_params() {
+        let command = StreamCommand {
+            command_type: "unsubscribe",
+            stream: "hashtag".to_string(),
+            params: BTreeMap::new(),
+        };
+        let json = serde_json::to_string(&command).unwrap();
+        assert_eq!(json, r#"{"type":"unsubscribe","stream":"hashtag"}"#);
+    }
 
-        builder.field("kind", &self.kind);
-        builder.finish()
+    #[test]
+    fn delay_for_is_capped_at_max_delay_even_for_large_attempts() {
+        let policy = ReconnectPolicy {
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            factor: 2.0,
+            max_retries: None,
+        };
+        // Uncapped this would be base_delay * 2^100, far beyond the range of a
+        // Duration, and used to panic when building the Duration.
+        let delay = policy.delay_for(100);
+        assert!(delay >= policy.max_delay);
+        assert!(delay <= policy.max_delay.mul_f64(1.2));
+    }
+
+    #[test]
+    fn delay_for_grows_with_attempt_before_hitting_the_cap() {
+        let policy = ReconnectPolicy {
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            factor: 2.0,
+            max_retries: None,
+        };
+        let first = policy.delay_for(0);
+        let second = policy.delay_for(1);
+        assert!(first >= policy.base_delay);
+        assert!(first <= policy.base_delay.mul_f64(1.2));
+        assert!(second > first);
     }
 }
@@ -0,0 +1,30 @@
+use std::time::Duration;
+
+use crate::entities;
+
+/// A single item produced by a streaming connection: either a decoded timeline
+/// event or a connection-lifecycle notification.
+#[derive(Debug, Clone)]
+pub enum Message {
+    Update(entities::Status),
+    Notification(entities::Notification),
+    Conversation(entities::Conversation),
+    Delete(String),
+    Heartbeat(Duration),
+    /// A connection attempt has started.
+    Connecting(),
+    /// The connection attempt succeeded and the socket is live.
+    Connected(),
+    /// A connection attempt failed or an established connection was lost; the
+    /// string describes the failure.
+    Error(String),
+    /// Backing off before the next reconnect attempt.
+    Reconnecting { attempt: u32, delay: Duration },
+    /// The server closed the connection with the given close code.
+    Closed { code: u16 },
+}
+
+/// Implemented by streaming transports to deliver parsed [`Message`]s to a callback.
+pub trait Streaming {
+    fn listen(&self, callback: Box<dyn Fn(Message)>);
+}